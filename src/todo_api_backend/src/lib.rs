@@ -2,16 +2,185 @@ use candid::CandidType;
 use ic_cdk::api::caller as caller_api;
 use ic_cdk::export::candid;
 use ic_cdk_macros::*;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+
+mod vetkd_types;
+use vetkd_types::{
+    VetKDCurve, VetKDEncryptedKeyReply, VetKDEncryptedKeyRequest, VetKDKeyId,
+    VetKDPublicKeyReply, VetKDPublicKeyRequest,
+};
 
 type PrincipalName = Vec<u8>;
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Memory ids for the stable structures below. These must never be reused
+// for a different purpose once shipped, or upgraded canisters will read
+// garbage.
+const NEXT_TODO_MEM_ID: MemoryId = MemoryId::new(0);
+const TODO_BY_USER_MEM_ID: MemoryId = MemoryId::new(1);
+const USERNAMES_MEM_ID: MemoryId = MemoryId::new(2);
+const PRINCIPAL_TO_USERNAME_MEM_ID: MemoryId = MemoryId::new(3);
+const ACL_MEM_ID: MemoryId = MemoryId::new(4);
+
+const MAX_PRINCIPAL_BYTES: u32 = 29;
+const MAX_USERNAME_BYTES: u32 = 256;
+const MAX_ACL_GRANTS: usize = 128;
+// MAX_ACL_GRANTS entries, each a (principal bytes, bool) pair. Like
+// TodoList::MAX_SIZE, this goes through candid::encode_one, which adds a
+// length prefix per Vec<u8> element plus an outer vec length prefix and
+// type table, none of which are free — budget a generous 16 bytes/entry of
+// framing on top of the raw principal bytes, plus a flat allowance for the
+// rest.
+const MAX_ACL_SIZE: u32 = MAX_ACL_GRANTS as u32 * (MAX_PRINCIPAL_BYTES + 1 + 16) + 256;
 
 #[derive(Clone, CandidType, Serialize, Deserialize)]
 pub struct Todo {
     id: u128,
     task: String,
+    /// Nanosecond timestamp (`ic_cdk::api::time()`) of the last write to
+    /// this todo, used as the last-writer-wins clock in [merge_todos].
+    updated_at: u64,
+    /// Tombstone flag: a deleted todo is kept around (rather than removed)
+    /// so that its deletion can win a last-writer-wins merge against a
+    /// stale, concurrently-edited copy.
+    deleted: bool,
+    done: bool,
+    tags: Vec<String>,
+}
+
+/// Filter passed to [query_todos]. Each set field narrows the results;
+/// unset fields ([None], or an empty `any_tags`) match everything.
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct TodoFilter {
+    done: Option<bool>,
+    any_tags: Vec<String>,
+    text_contains: Option<String>,
+}
+
+/// Keeps whichever of `a`/`b` is more recent by [Todo::updated_at],
+/// breaking ties by comparing `task` bytes (then `deleted`, `done` and
+/// `tags`) so the merge is deterministic and commutative regardless of
+/// argument order.
+fn newer_todo(a: Todo, b: Todo) -> Todo {
+    match a.updated_at.cmp(&b.updated_at) {
+        std::cmp::Ordering::Greater => a,
+        std::cmp::Ordering::Less => b,
+        std::cmp::Ordering::Equal => {
+            if (a.task.as_bytes(), a.deleted, a.done, &a.tags)
+                >= (b.task.as_bytes(), b.deleted, b.done, &b.tags)
+            {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub enum TodoError {
+    Unauthorized,
+    TooManyUsers,
+    TooManyTodos,
+    TaskTooLong { max: usize },
+    InvalidId,
+    NotFound,
+    UsernameTaken,
+    UsernameTooLong { max: usize },
+    TooManyTags { max: usize },
+    TagTooLong { max: usize },
+    VetKdCallFailed,
+    TooManyGrants,
+}
+
+/// Key wrapper so we can implement the stable-structures traits for a
+/// principal's raw bytes without running afoul of the orphan rule.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct PrincipalKey(PrincipalName);
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.clone())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        PrincipalKey(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = MAX_PRINCIPAL_BYTES;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A user's full todo list, stored as a single stable-structures value so
+/// that `add_todo`/`update_todo`/`delete_todo` can read-modify-write it in
+/// one `StableBTreeMap` operation.
+#[derive(Clone, Default, CandidType, Serialize, Deserialize)]
+struct TodoList(Vec<Todo>);
+
+impl Storable for TodoList {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode TodoList"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode TodoList")
+    }
+}
+
+impl BoundedStorable for TodoList {
+    // MAX_TODO_PER_USER todos, each bounded by MAX_TODO_CHARS and
+    // MAX_TAGS_PER_TODO tags of MAX_TAG_CHARS. The limits are enforced as
+    // `chars().count()`, not byte length, so budget 4 bytes/char (the
+    // UTF-8 worst case) rather than 1, plus a generous allowance for the
+    // `id`/`updated_at`/`deleted`/`done` fields and the Candid record/vec
+    // framing.
+    const MAX_SIZE: u32 = 500 * (1000 * 4 + 10 * 32 * 4 + 96);
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Key wrapper for the username registry, mirroring [PrincipalKey].
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct UsernameKey(String);
+
+impl Storable for UsernameKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.as_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        UsernameKey(String::from_utf8(bytes.into_owned()).expect("invalid utf-8 username"))
+    }
+}
+
+impl BoundedStorable for UsernameKey {
+    const MAX_SIZE: u32 = MAX_USERNAME_BYTES;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// An owner's access-control list: `(grantee, can_write)` pairs granted via
+/// [share_with] and removed via [revoke].
+#[derive(Clone, Default, CandidType, Serialize, Deserialize)]
+struct AclList(Vec<(PrincipalName, bool)>);
+
+impl Storable for AclList {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode AclList"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode AclList")
+    }
+}
+
+impl BoundedStorable for AclList {
+    const MAX_SIZE: u32 = MAX_ACL_SIZE;
+    const IS_FIXED_SIZE: bool = false;
 }
 
 thread_local! {
@@ -19,21 +188,119 @@ thread_local! {
     static MAX_USERS: usize = 1_000;
     static MAX_TODO_PER_USER: usize = 500;
     static MAX_TODO_CHARS: usize = 1000;
+    static MAX_USERNAME_CHARS: usize = 64;
+    static MAX_TAGS_PER_TODO: usize = 10;
+    static MAX_TAG_CHARS: usize = 32;
 
-    pub static NEXT_TODO: RefCell<u128> = RefCell::new(0);
-    pub static TODO_BY_USER: RefCell<BTreeMap<PrincipalName, Vec<Todo>>> = RefCell::new(BTreeMap::new());
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    pub static NEXT_TODO: RefCell<StableCell<u128, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|mm| mm.borrow().get(NEXT_TODO_MEM_ID)),
+            0,
+        )
+        .expect("failed to init NEXT_TODO stable cell"),
+    );
+
+    pub static TODO_BY_USER: RefCell<StableBTreeMap<PrincipalKey, TodoList, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(TODO_BY_USER_MEM_ID))),
+    );
+
+    // username -> owning principal
+    pub static USERNAMES: RefCell<StableBTreeMap<UsernameKey, PrincipalKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(USERNAMES_MEM_ID))),
+    );
+
+    // reverse of USERNAMES, so a principal can look up its own username
+    pub static PRINCIPAL_TO_USERNAME: RefCell<StableBTreeMap<PrincipalKey, UsernameKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(PRINCIPAL_TO_USERNAME_MEM_ID))),
+    );
+
+    // owner principal -> list of principals (and write permission) shared with
+    pub static ACL: RefCell<StableBTreeMap<PrincipalKey, AclList, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|mm| mm.borrow().get(ACL_MEM_ID))),
+    );
 }
 
 fn caller() -> PrincipalName {
     caller_api().as_slice().to_owned()
 }
 
+fn caller_checked() -> Result<PrincipalName, TodoError> {
+    if caller_api() == candid::Principal::anonymous() {
+        return Err(TodoError::Unauthorized);
+    }
+    Ok(caller())
+}
+
+fn validate_tags(tags: &[String]) -> Result<(), TodoError> {
+    let max_tags = MAX_TAGS_PER_TODO.with(|m| *m);
+    if tags.len() > max_tags {
+        return Err(TodoError::TooManyTags { max: max_tags });
+    }
+    let max_tag_chars = MAX_TAG_CHARS.with(|m| *m);
+    if tags.iter().any(|tag| tag.chars().count() > max_tag_chars) {
+        return Err(TodoError::TagTooLong { max: max_tag_chars });
+    }
+    Ok(())
+}
+
+fn lookup_username(username: &str) -> Result<PrincipalKey, TodoError> {
+    USERNAMES.with(|usernames_ref| {
+        usernames_ref
+            .borrow()
+            .get(&UsernameKey(username.to_string()))
+            .ok_or(TodoError::NotFound)
+    })
+}
+
+fn has_write_access(owner: &PrincipalKey, grantee: &PrincipalName) -> bool {
+    ACL.with(|acl_ref| {
+        acl_ref
+            .borrow()
+            .get(owner)
+            .map(|acl| acl.0.iter().any(|(p, can_write)| p == grantee && *can_write))
+            .unwrap_or(false)
+    })
+}
+
+fn has_any_access(owner: &PrincipalKey, grantee: &PrincipalName) -> bool {
+    ACL.with(|acl_ref| {
+        acl_ref
+            .borrow()
+            .get(owner)
+            .map(|acl| acl.0.iter().any(|(p, _)| p == grantee))
+            .unwrap_or(false)
+    })
+}
+
+/// Resolves which list [caller] is operating on: their own (`owner ==
+/// None`) or another user's shared list, which requires a write grant in
+/// that owner's ACL.
+fn resolve_write_target(
+    owner: Option<String>,
+    caller: &PrincipalName,
+) -> Result<PrincipalKey, TodoError> {
+    match owner {
+        None => Ok(PrincipalKey(caller.clone())),
+        Some(username) => {
+            let owner_key = lookup_username(&username)?;
+            if owner_key.0 == *caller || has_write_access(&owner_key, caller) {
+                Ok(owner_key)
+            } else {
+                Err(TodoError::Unauthorized)
+            }
+        }
+    }
+}
+
 #[init]
 fn init() {}
 
 /// Returns the current number of users.
 fn get_user_count() -> usize {
-    TODO_BY_USER.with(|todo_ref| todo_ref.borrow().keys().len())
+    TODO_BY_USER.with(|todo_ref| todo_ref.borrow().len() as usize)
 }
 
 fn is_id_valid(id: u128) -> bool {
@@ -41,57 +308,214 @@ fn is_id_valid(id: u128) -> bool {
         .with(|max_todo_per_user| id < (*max_todo_per_user as u128) * (get_user_count() as u128))
 }
 
-/// Returns (a future of) this [caller]'s todos.
-/// Panics:
-///     [caller] is the unknown identity
-///     [caller] is not a registered user
+/// Returns this [caller]'s todos matching every set field of [filter],
+/// excluding tombstoned (deleted) entries. Filtering server-side avoids
+/// shipping the whole list to the client just to display a filtered view.
+/// Returns an empty [Vec] for the anonymous identity or a caller with no
+/// todo list, rather than erroring.
 #[query]
-fn get_todos() -> Vec<Todo> {
-    let user = caller();
-    TODO_BY_USER.with(|todo_ref| todo_ref.borrow().get(&user).cloned().unwrap_or_default())
+fn query_todos(filter: TodoFilter) -> Vec<Todo> {
+    let user = PrincipalKey(caller());
+    TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&user)
+            .map(|list| {
+                list.0
+                    .into_iter()
+                    .filter(|t| !t.deleted)
+                    .filter(|t| filter.done.map_or(true, |want| t.done == want))
+                    .filter(|t| {
+                        filter.any_tags.is_empty()
+                            || t.tags.iter().any(|tag| filter.any_tags.contains(tag))
+                    })
+                    .filter(|t| {
+                        filter
+                            .text_contains
+                            .as_ref()
+                            .map_or(true, |needle| t.task.contains(needle.as_str()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
 }
 
-/// Delete this [caller]'s todo with given id. If none of the
-/// existing todos have this id, do nothing.
+/// Delete a todo with given id by setting its tombstone, on [caller]'s own
+/// list (`owner == None`) or on a username's list that was shared with
+/// write access. If none of the existing todos have this id, do nothing.
 /// [id]: the id of the todo to be deleted
 ///
-/// Panics:
-///      [caller] is the anonymous identity
-///      [caller] is not a registered user
-///      [id] is get_user_countsonable; see [is_id_valid]
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity,
+///          or lacks write access to [owner]'s list
+///      [TodoError::NotFound] if [owner] is not a registered username
+///      [TodoError::InvalidId] if [id] is unreasonable; see [is_id_valid]
 #[update]
-fn delete_todo(todo_id: u128) {
-    let user = caller();
-    assert!(is_id_valid(todo_id));
+fn delete_todo(owner: Option<String>, todo_id: u128) -> Result<(), TodoError> {
+    let caller = caller_checked()?;
+    let user = resolve_write_target(owner, &caller)?;
+    if !is_id_valid(todo_id) {
+        return Err(TodoError::InvalidId);
+    }
     // shared ownership borrowing
     TODO_BY_USER.with(|todo_ref| {
         let mut writer = todo_ref.borrow_mut();
-        if let Some(v) = writer.get_mut(&user) {
-            v.retain(|item| item.id != todo_id);
+        if let Some(mut list) = writer.get(&user) {
+            if let Some(todo) = list.0.iter_mut().find(|item| item.id == todo_id) {
+                todo.deleted = true;
+                todo.updated_at = ic_cdk::api::time();
+                writer.insert(user, list);
+            }
         }
     });
+    Ok(())
 }
 
-/// Returns (a future of) this [caller]'s todos.
-/// get_user_count
-///     [caller] is the unknown identity
-///     [caller] is not a registered user
-///     [todo.task] exceeds [MAX_TODO_CHARS]
-///     [todo.id] is unreasonable; see [is_id_valid]
+/// Merges a client's full local snapshot of their todos into the stored
+/// list, keeping the last-writer-wins version of each todo by
+/// [Todo::updated_at] (see [newer_todo]). Todos not present locally are
+/// left untouched; ids not yet known to the canister are inserted, and
+/// [NEXT_TODO] is advanced past the highest such id so [add_todo] can
+/// never later mint an id this caller already claimed offline.
+///
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity
+///      [TodoError::TaskTooLong] if any incoming todo's task exceeds
+///          [MAX_TODO_CHARS]
+///      [TodoError::InvalidId] if any incoming todo's id is unreasonable;
+///          see [is_id_valid]
+///      [TodoError::TooManyTags]/[TodoError::TagTooLong] if any incoming
+///          todo's tags exceed [MAX_TAGS_PER_TODO]/[MAX_TAG_CHARS]
+///      [TodoError::TooManyTodos] if merging in the new ids would exceed
+///          [MAX_TODO_PER_USER]
 #[update]
-fn update_todo(todos: Todo) {
-    let user = caller();
-    assert!(todos.task.chars().count() <= MAX_TODO_CHARS.with(|mnc| *mnc));
-    assert!(is_id_valid(todos.id));
+fn merge_todos(incoming: Vec<Todo>) -> Result<(), TodoError> {
+    let user = PrincipalKey(caller_checked()?);
+    let max_todo_chars = MAX_TODO_CHARS.with(|m| *m);
+    for todo in &incoming {
+        if todo.task.chars().count() > max_todo_chars {
+            return Err(TodoError::TaskTooLong { max: max_todo_chars });
+        }
+        if !is_id_valid(todo.id) {
+            return Err(TodoError::InvalidId);
+        }
+        validate_tags(&todo.tags)?;
+    }
 
     TODO_BY_USER.with(|todos_ref| {
         let mut writer = todos_ref.borrow_mut();
-        if let Some(old_todo) = writer
-            .get_mut(&user)
-            .and_then(|td| td.iter_mut().find(|t| t.id == todos.id))
-        {
-            old_todo.task = todos.task;
+        let mut list = writer.get(&user).unwrap_or_default();
+
+        let mut new_ids: Vec<u128> = vec![];
+        for todo in &incoming {
+            let is_new = !list.0.iter().any(|t| t.id == todo.id) && !new_ids.contains(&todo.id);
+            if is_new {
+                new_ids.push(todo.id);
+            }
+        }
+        let max_todo_per_user = MAX_TODO_PER_USER.with(|m| *m);
+        if list.0.len() + new_ids.len() > max_todo_per_user {
+            return Err(TodoError::TooManyTodos);
+        }
+
+        let max_new_id = new_ids.iter().copied().max();
+
+        for todo in incoming {
+            match list.0.iter().position(|t| t.id == todo.id) {
+                Some(index) => {
+                    let existing = list.0.swap_remove(index);
+                    list.0.push(newer_todo(existing, todo));
+                }
+                None => list.0.push(todo),
+            }
+        }
+
+        writer.insert(user, list);
+
+        // A merged-in id may fall inside the range NEXT_TODO has not yet
+        // reached; advance it past any such id so add_todo can never mint
+        // the same id again (see merge_todos's doc comment).
+        if let Some(max_new_id) = max_new_id {
+            NEXT_TODO.with(|counter_ref| {
+                let mut counter = counter_ref.borrow_mut();
+                if *counter.get() < max_new_id {
+                    counter
+                        .set(max_new_id)
+                        .expect("failed to persist NEXT_TODO");
+                }
+            });
         }
+
+        Ok(())
+    })
+}
+
+/// Updates the todo with the given id, on [caller]'s own list (`owner ==
+/// None`) or on a username's list that was shared with write access.
+///
+/// Errors:
+///     [TodoError::Unauthorized] if [caller] is the anonymous identity,
+///         or lacks write access to [owner]'s list
+///     [TodoError::NotFound] if [owner] is not a registered username, or
+///         no todo with [todo.id] exists for the target list
+///     [TodoError::TaskTooLong] if [todo.task] exceeds [MAX_TODO_CHARS]
+///     [TodoError::InvalidId] if [todo.id] is unreasonable; see [is_id_valid]
+#[update]
+fn update_todo(owner: Option<String>, todos: Todo) -> Result<(), TodoError> {
+    let caller = caller_checked()?;
+    let user = resolve_write_target(owner, &caller)?;
+    let max_todo_chars = MAX_TODO_CHARS.with(|mnc| *mnc);
+    if todos.task.chars().count() > max_todo_chars {
+        return Err(TodoError::TaskTooLong { max: max_todo_chars });
+    }
+    if !is_id_valid(todos.id) {
+        return Err(TodoError::InvalidId);
+    }
+    validate_tags(&todos.tags)?;
+
+    TODO_BY_USER.with(|todos_ref| {
+        let mut writer = todos_ref.borrow_mut();
+        let mut list = writer.get(&user).ok_or(TodoError::NotFound)?;
+        let old_todo = list
+            .0
+            .iter_mut()
+            .find(|t| t.id == todos.id)
+            .ok_or(TodoError::NotFound)?;
+        old_todo.task = todos.task;
+        old_todo.tags = todos.tags;
+        old_todo.deleted = false;
+        old_todo.updated_at = ic_cdk::api::time();
+        writer.insert(user, list);
+        Ok(())
+    })
+}
+
+/// Toggles the `done` state of this [caller]'s todo with the given id.
+///
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity
+///      [TodoError::InvalidId] if [id] is unreasonable; see [is_id_valid]
+///      [TodoError::NotFound] if no todo with [id] exists for [caller]
+#[update]
+fn toggle_done(todo_id: u128) -> Result<(), TodoError> {
+    let user = PrincipalKey(caller_checked()?);
+    if !is_id_valid(todo_id) {
+        return Err(TodoError::InvalidId);
+    }
+
+    TODO_BY_USER.with(|todos_ref| {
+        let mut writer = todos_ref.borrow_mut();
+        let mut list = writer.get(&user).ok_or(TodoError::NotFound)?;
+        let todo = list
+            .0
+            .iter_mut()
+            .find(|t| t.id == todo_id)
+            .ok_or(TodoError::NotFound)?;
+        todo.done = !todo.done;
+        todo.updated_at = ic_cdk::api::time();
+        writer.insert(user, list);
+        Ok(())
     })
 }
 
@@ -99,38 +523,227 @@ fn update_todo(todos: Todo) {
 ///      [todo]: (encrypted) content of this todo
 ///
 /// Returns:
-///      Future of unit
-/// Panics:
-///      [caller] is the anonymous identity
-///      [caller] is not a registered user
-///      [todo] exceeds [MAX_TODO_CHARS]
-///      User already has [MAX_TODOS_PER_USER] todos
-///      [todo] would be for a new user and [MAX_USERS] is exceeded
+///      The new todo's id.
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity
+///      [TodoError::TaskTooLong] if [todo] exceeds [MAX_TODO_CHARS]
+///      [TodoError::TooManyTodos] if [caller] already has [MAX_TODO_PER_USER] todos
+///      [TodoError::TooManyUsers] if [todo] would be for a new user and [MAX_USERS] is exceeded
 #[update]
-fn add_todo(task: String) {
-    let user = caller();
-    assert!(task.chars().count() <= MAX_TODO_CHARS.with(|mtc| *mtc));
-    let todo_id = NEXT_TODO.with(|counter_ref| {
-        let mut writer = counter_ref.borrow_mut();
-        *writer += 1;
-        *writer
-    });
+fn add_todo(task: String) -> Result<u128, TodoError> {
+    let user = PrincipalKey(caller_checked()?);
+    let max_todo_chars = MAX_TODO_CHARS.with(|mtc| *mtc);
+    if task.chars().count() > max_todo_chars {
+        return Err(TodoError::TaskTooLong { max: max_todo_chars });
+    }
 
     let user_count = get_user_count();
     TODO_BY_USER.with(|todos_ref| {
         let mut writer = todos_ref.borrow_mut();
-        let user_todos = writer.entry(user).or_insert_with(|| {
-            // caller unknown ==> check invariants
-            // A. can we add a new user?
-            assert!(MAX_USERS.with(|mu| user_count < *mu));
-            vec![]
-        });
+        let is_new_user = writer.get(&user).is_none();
+        if is_new_user && !MAX_USERS.with(|mu| user_count < *mu) {
+            return Err(TodoError::TooManyUsers);
+        }
+        let mut list = writer.get(&user).unwrap_or_default();
 
-        assert!(user_todos.len() < MAX_TODO_PER_USER.with(|mtpu| *mtpu));
+        if list.0.len() >= MAX_TODO_PER_USER.with(|mtpu| *mtpu) {
+            return Err(TodoError::TooManyTodos);
+        }
+
+        let todo_id = NEXT_TODO.with(|counter_ref| {
+            let mut counter = counter_ref.borrow_mut();
+            let next = counter.get() + 1;
+            counter.set(next).expect("failed to persist NEXT_TODO");
+            next
+        });
 
-        user_todos.push(Todo {
+        list.0.push(Todo {
             id: todo_id,
             task: task,
+            updated_at: ic_cdk::api::time(),
+            deleted: false,
+            done: false,
+            tags: vec![],
         });
+        writer.insert(user, list);
+        Ok(todo_id)
+    })
+}
+
+/// Registers [caller] under the given username so other users can
+/// [share_with] them without learning their raw principal.
+///
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity
+///      [TodoError::UsernameTooLong] if [name] exceeds [MAX_USERNAME_CHARS]
+///      [TodoError::UsernameTaken] if [name] is already registered
+#[update]
+fn register_username(name: String) -> Result<(), TodoError> {
+    let user = caller_checked()?;
+    let max_username_chars = MAX_USERNAME_CHARS.with(|m| *m);
+    if name.chars().count() > max_username_chars {
+        return Err(TodoError::UsernameTooLong {
+            max: max_username_chars,
+        });
+    }
+
+    let key = UsernameKey(name);
+    USERNAMES.with(|usernames_ref| {
+        let mut writer = usernames_ref.borrow_mut();
+        if writer.get(&key).is_some() {
+            return Err(TodoError::UsernameTaken);
+        }
+        writer.insert(key.clone(), PrincipalKey(user.clone()));
+        Ok(())
+    })?;
+
+    PRINCIPAL_TO_USERNAME.with(|p2u_ref| {
+        p2u_ref.borrow_mut().insert(PrincipalKey(user), key);
     });
+    Ok(())
+}
+
+/// Returns [caller]'s own registered username, if any.
+#[query]
+fn username_of_caller() -> Option<String> {
+    PRINCIPAL_TO_USERNAME
+        .with(|p2u_ref| p2u_ref.borrow().get(&PrincipalKey(caller())).map(|k| k.0))
+}
+
+/// Grants `username` access to [caller]'s todo list, with write access iff
+/// [can_write] is set. Calling again for the same username replaces the
+/// existing grant (and does not count against [MAX_ACL_GRANTS]).
+///
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity
+///      [TodoError::NotFound] if [username] is not a registered username
+///      [TodoError::TooManyGrants] if [username] is a new grantee and
+///          [caller]'s ACL already has [MAX_ACL_GRANTS] entries
+#[update]
+fn share_with(username: String, can_write: bool) -> Result<(), TodoError> {
+    let owner = PrincipalKey(caller_checked()?);
+    let grantee = lookup_username(&username)?;
+
+    ACL.with(|acl_ref| {
+        let mut writer = acl_ref.borrow_mut();
+        let mut acl = writer.get(&owner).unwrap_or_default();
+        let is_new_grantee = !acl.0.iter().any(|(p, _)| *p == grantee.0);
+        if is_new_grantee && acl.0.len() >= MAX_ACL_GRANTS {
+            return Err(TodoError::TooManyGrants);
+        }
+        acl.0.retain(|(p, _)| *p != grantee.0);
+        acl.0.push((grantee.0, can_write));
+        writer.insert(owner, acl);
+        Ok(())
+    })
+}
+
+/// Revokes any access previously granted to `username` on [caller]'s todo
+/// list. Does nothing if no such grant exists.
+///
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity
+///      [TodoError::NotFound] if [username] is not a registered username
+#[update]
+fn revoke(username: String) -> Result<(), TodoError> {
+    let owner = PrincipalKey(caller_checked()?);
+    let grantee = lookup_username(&username)?;
+
+    ACL.with(|acl_ref| {
+        let mut writer = acl_ref.borrow_mut();
+        if let Some(mut acl) = writer.get(&owner) {
+            acl.0.retain(|(p, _)| *p != grantee.0);
+            writer.insert(owner, acl);
+        }
+    });
+    Ok(())
+}
+
+/// Returns `owner`'s todos, if [caller] has been granted (read or write)
+/// access via [share_with].
+///
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity,
+///          or has not been granted access to [owner]'s list
+///      [TodoError::NotFound] if [owner] is not a registered username
+#[query]
+fn get_shared_todos(owner: String) -> Result<Vec<Todo>, TodoError> {
+    let user = caller_checked()?;
+    let owner_key = lookup_username(&owner)?;
+    if !has_any_access(&owner_key, &user) {
+        return Err(TodoError::Unauthorized);
+    }
+
+    Ok(TODO_BY_USER.with(|todo_ref| {
+        todo_ref
+            .borrow()
+            .get(&owner_key)
+            .map(|list| list.0.into_iter().filter(|t| !t.deleted).collect())
+            .unwrap_or_default()
+    }))
+}
+
+const VETKD_MANAGEMENT_CANISTER: &str = "aaaaa-aa";
+
+fn vetkd_key_id() -> VetKDKeyId {
+    VetKDKeyId {
+        curve: VetKDCurve::Bls12_381,
+        name: "test_key_1".to_string(),
+    }
+}
+
+/// Returns the public verification key for this canister's vetKD key, so
+/// the frontend can verify the encrypted key it receives from
+/// [encrypted_symmetric_key_for_caller].
+///
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity
+#[update]
+async fn symmetric_key_verification_key() -> Result<Vec<u8>, TodoError> {
+    caller_checked()?;
+    let request = VetKDPublicKeyRequest {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: vetkd_key_id(),
+    };
+
+    let (reply,): (VetKDPublicKeyReply,) = ic_cdk::api::call::call(
+        candid::Principal::from_text(VETKD_MANAGEMENT_CANISTER).unwrap(),
+        "vetkd_public_key",
+        (request,),
+    )
+    .await
+    .map_err(|_| TodoError::VetKdCallFailed)?;
+
+    Ok(reply.public_key)
+}
+
+/// Derives an encrypted symmetric key for this [caller], encrypted under
+/// the caller-supplied [transport_public_key]. Only the caller can decrypt
+/// the result, so the frontend can use it to derive an AES-GCM key and
+/// keep todo contents end-to-end encrypted.
+///
+/// Errors:
+///      [TodoError::Unauthorized] if [caller] is the anonymous identity
+#[update]
+async fn encrypted_symmetric_key_for_caller(
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, TodoError> {
+    let user = caller_checked()?;
+    let request = VetKDEncryptedKeyRequest {
+        derivation_id: user,
+        public_key_derivation_path: vec![],
+        key_id: vetkd_key_id(),
+        encryption_public_key: transport_public_key,
+    };
+
+    let (reply,): (VetKDEncryptedKeyReply,) = ic_cdk::api::call::call(
+        candid::Principal::from_text(VETKD_MANAGEMENT_CANISTER).unwrap(),
+        "vetkd_derive_encrypted_key",
+        (request,),
+    )
+    .await
+    .map_err(|_| TodoError::VetKdCallFailed)?;
+
+    Ok(reply.encrypted_key)
 }
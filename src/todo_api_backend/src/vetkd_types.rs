@@ -0,0 +1,46 @@
+//! Candid types for the IC management canister's vetKD API.
+//!
+//! These mirror the (not yet published) management canister interface for
+//! `vetkd_public_key` and `vetkd_derive_encrypted_key`; we can't depend on
+//! them from `ic-cdk` yet, so they're declared here and called via
+//! `ic_cdk::api::call::call` against the `aaaaa-aa` management canister.
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub enum VetKDCurve {
+    #[serde(rename = "bls12_381")]
+    Bls12_381,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct VetKDKeyId {
+    pub curve: VetKDCurve,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct VetKDPublicKeyRequest {
+    pub canister_id: Option<candid::Principal>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub key_id: VetKDKeyId,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct VetKDPublicKeyReply {
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct VetKDEncryptedKeyRequest {
+    pub derivation_id: Vec<u8>,
+    pub public_key_derivation_path: Vec<Vec<u8>>,
+    pub key_id: VetKDKeyId,
+    pub encryption_public_key: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct VetKDEncryptedKeyReply {
+    pub encrypted_key: Vec<u8>,
+}